@@ -40,21 +40,504 @@ use itertools::join;
 
 use crate::sql::primary_agg::primary_agg;
 use super::{
+    Table,
     TableSql,
     CutSql,
     DrilldownSql,
     MeasureSql,
+    MemberType,
     RcaSql,
 };
 
+/// Allocator for deterministic, collision-free SQL identifiers.
+///
+/// `calculate` used to recover internal column names after the fact with string
+/// surgery (`a.replace("final_m0", "a")`, `replace("select , ", "select ")`),
+/// which silently corrupts the query as soon as a user drill or measure name
+/// happens to contain one of those tokens. `Alias` instead hands out names at
+/// construction time, each carrying a prefix that user columns can't produce:
+/// `tag` names the derived aggregates (`a`..`d`) and `next` numbers the nested
+/// subqueries, so the identifiers are chosen up front rather than rediscovered.
+///
+/// One rename still goes through text: the measure columns `primary_agg` emits as
+/// `final_m0`, `final_m1`, … are rewritten onto allocated names by [`rename_measures`].
+/// That pass matches each token only at an identifier boundary, so it neither lets
+/// `final_m1` clobber `final_m10` nor touches a user column that merely contains one of those
+/// tokens, such as `myfinal_m0`.
+/// Threading the allocator into `primary_agg` so it stamps the final names at
+/// construction would remove the text pass entirely, but that function lives outside
+/// this module's source snapshot, so its signature can't be changed from here.
+pub(crate) struct Alias {
+    prefix: &'static str,
+    counter: usize,
+}
+
+impl Alias {
+    pub(crate) fn new(prefix: &'static str) -> Self {
+        Alias { prefix, counter: 0 }
+    }
+
+    /// Allocate the next identifier in the sequence, e.g. `_rca_0`, `_rca_1`.
+    pub(crate) fn next(&mut self) -> String {
+        let id = format!("{}{}", self.prefix, self.counter);
+        self.counter += 1;
+        id
+    }
+
+    /// Name a derived aggregate by tag (`a`, `b`, `c`, `d`), keeping the prefix so
+    /// it can't collide with a user column that is literally named `a`.
+    pub(crate) fn tag(&self, tag: &str) -> String {
+        format!("{}{}", self.prefix, tag)
+    }
+}
+
+/// Join `head` and `tail` with a comma, dropping either side when it is empty.
+///
+/// Replaces the `"select , "` / `"group by )"` patch-ups: an empty drill list no
+/// longer leaves a dangling leading comma to be scrubbed out afterwards.
+fn comma_join(head: &str, tail: &str) -> String {
+    match (head.is_empty(), tail.is_empty()) {
+        (true, _) => tail.to_owned(),
+        (_, true) => head.to_owned(),
+        (false, false) => format!("{}, {}", head, tail),
+    }
+}
+
+/// Rename the measure columns `primary_agg` emits onto their allocated targets.
+///
+/// `primary_agg` names its output measures `final_m0`, `final_m1`, …; here `final_m0` is the rca
+/// measure (renamed to `rca_col`) and `final_m{i}` for `i` in `1..=ext` are the external measures
+/// (renamed to `m{i}`). Each token is matched only at an identifier boundary, so `final_m1` can't
+/// clobber `final_m10` (the substring hazard of a plain `str::replace` fold) and a user column
+/// such as `myfinal_m0` is left untouched.
+///
+/// Threading the allocator into `primary_agg` so it stamps these names at construction would drop
+/// the text pass entirely, but that function lives outside this module's source snapshot; the
+/// boundary-aware match keeps the `final_m{i}` contract robust until then.
+fn rename_measures(sql: &str, rca_col: &str, ext: usize) -> String {
+    let mut renames: Vec<(String, String)> = vec![("final_m0".to_owned(), rca_col.to_owned())];
+    renames.extend((1..=ext).map(|i| (format!("final_m{}", i), format!("m{}", i))));
+    // longest source first so `final_m10` is considered before `final_m1`.
+    renames.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+
+    let is_ident = |c: char| c.is_ascii_alphanumeric() || c == '_';
+
+    let mut out = String::with_capacity(sql.len());
+    let mut rest = sql;
+    let mut prev: Option<char> = None;
+    'outer: while !rest.is_empty() {
+        let leading_ok = prev.map_or(true, |c| !is_ident(c));
+        if leading_ok {
+            for (from, to) in &renames {
+                if let Some(after) = rest.strip_prefix(from.as_str()) {
+                    if after.chars().next().map_or(true, |c| !is_ident(c)) {
+                        out.push_str(to);
+                        prev = to.chars().last();
+                        rest = after;
+                        continue 'outer;
+                    }
+                }
+            }
+        }
+        let ch = rest.chars().next().unwrap();
+        out.push(ch);
+        prev = Some(ch);
+        rest = &rest[ch.len_utf8()..];
+    }
+    out
+}
+
+/// Render a cut as a ClickHouse membership predicate, e.g. `product_id in (3, 7)`.
+///
+/// Used only by the opt-in drill_2 cut (see [`calculate`]); the ordinary cuts are handled
+/// inside `primary_agg`, but that drill_2 filter is pushed past the pre-aggregation into the
+/// pivot melt, so it is the one cut rca has to spell out itself. Text members are quoted, in
+/// keeping with how the rest of the tree distinguishes `MemberType`.
+fn membership_predicate(cut: &CutSql) -> String {
+    let members = match cut.member_type {
+        MemberType::Text => join(cut.members.iter().map(|m| format!("'{}'", m)), ", "),
+        MemberType::NonText => join(cut.members.iter().cloned(), ", "),
+    };
+    format!("{} in ({})", cut.column, members)
+}
+
+/// The strategy `calculate` chose to derive c and d from a and b.
+///
+/// Exposed in the return value so callers can log the decision, and accepted as
+/// an override when the estimate is known to be off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RcaPlan {
+    /// groupArray + Array Join pivot: a single scan builds an array of drill_2
+    /// members per drill_1 group and melts it back out.
+    Pivot,
+    /// Independent group-bys of a and b, inner-joined back on their shared keys.
+    Join,
+}
+
+/// Per-drill cardinality estimates used to pick between [`RcaPlan`] variants.
+///
+/// Sourced from cube metadata (distinct member counts, fact-table row counts).
+/// These are only estimates; the cost model just needs them in the right ballpark.
+#[derive(Debug, Clone, Copy)]
+pub struct RcaCardinality {
+    /// Estimated distinct values of the first rca drill.
+    pub drill_1: u64,
+    /// Estimated distinct values of the second rca drill (the pivoted one).
+    pub drill_2: u64,
+    /// Estimated row count of the aggregated `a` relation.
+    pub a_rows: u64,
+}
+
+impl RcaCardinality {
+    /// One scan over a, then a groupArray of every drill_2 member materialized per drill_1 group.
+    /// That array is the term that blows up: its total size is proportional to `drill_1 * drill_2`,
+    /// so a high-cardinality drill_2 is exactly what makes the pivot expensive (and memory-hungry
+    /// in ClickHouse).
+    fn pivot_cost(&self) -> f64 {
+        self.a_rows as f64 + self.drill_1 as f64 * self.drill_2 as f64
+    }
+
+    /// a is scanned a second time for the reduction and hash-joined back on its keys. No array is
+    /// materialized, so the cost stays roughly linear in the aggregated rows — it doesn't explode
+    /// with drill_2, which is why the join wins once drill_2 is large.
+    fn join_cost(&self) -> f64 {
+        2.0 * self.a_rows as f64
+    }
+
+    /// The cheaper plan under this estimate, ties going to the single-scan pivot.
+    fn cheaper_plan(&self) -> RcaPlan {
+        if self.pivot_cost() <= self.join_cost() {
+            RcaPlan::Pivot
+        } else {
+            RcaPlan::Join
+        }
+    }
+}
+
+/// Declares which level keys are globally unique.
+///
+/// A drilldown's `level_columns` are ordered coarsest -> finest (e.g. year -> month -> day) and
+/// form a hierarchy in which a finer key determines its ancestors. But only a *globally* unique
+/// key carries that dependency on its own: a day-of-epoch fixes its month and year, whereas a
+/// day-of-month is unique only within its parent and determines nothing without the month in the
+/// key too. This registry (populated from cube metadata) lets the GROUP BY pruning pass tell the
+/// two apart; an empty registry collapses nothing and reproduces the un-pruned grouping.
+#[derive(Debug, Default, Clone)]
+pub struct FunctionalDeps {
+    globally_unique_keys: Vec<String>,
+}
+
+impl FunctionalDeps {
+    pub fn new(globally_unique_keys: Vec<String>) -> Self {
+        FunctionalDeps { globally_unique_keys }
+    }
+
+    fn is_globally_unique(&self, key_column: &str) -> bool {
+        self.globally_unique_keys.iter().any(|k| k == key_column)
+    }
+}
+
+/// One drilldown's grouping set after pruning functionally-dependent ancestors.
+struct PrunedDrilldown {
+    /// Key columns that must remain in the GROUP BY.
+    group_by: Vec<String>,
+    /// Select expressions: bare key for retained levels, `any(col)` for determined ones.
+    select: Vec<String>,
+}
+
+/// Prune a single drilldown's GROUP BY against its declared functional dependencies.
+///
+/// We find the finest level whose key is globally unique; that key alone fixes every coarser
+/// (ancestor) level, so we group only by that level and everything finer, and carry the coarser
+/// key/name columns with `any(col)`. Name columns are always determined by their own key, so they
+/// are carried with `any(col)` in every case rather than bloating the grouping set. When no level
+/// is declared globally unique we keep all keys, preserving the original grain.
+fn prune_drilldown(drill: &DrilldownSql, fd: &FunctionalDeps) -> PrunedDrilldown {
+    let levels = &drill.level_columns;
+
+    // finest index whose key is globally unique; everything coarser is determined by it.
+    let cut = levels.iter()
+        .rposition(|l| fd.is_globally_unique(&l.key_column))
+        .unwrap_or(0);
+
+    let mut group_by = Vec::new();
+    let mut select = Vec::new();
+
+    for (i, l) in levels.iter().enumerate() {
+        if i >= cut {
+            // retained: the key stays in the grouping set.
+            group_by.push(l.key_column.clone());
+            select.push(l.key_column.clone());
+        } else {
+            // ancestor determined by the globally-unique cut level.
+            select.push(format!("any({key}) as {key}", key=l.key_column));
+        }
+        // the name column is functionally determined by its key either way.
+        if let Some(ref name) = l.name_column {
+            select.push(format!("any({name}) as {name}", name=name));
+        }
+    }
+
+    PrunedDrilldown { group_by, select }
+}
+
+/// Build the (GROUP BY, SELECT) column strings for a list of drilldowns, with FD pruning applied.
+fn grouped_drills(drills: &[DrilldownSql], fd: &FunctionalDeps) -> (String, String) {
+    let mut group_by = Vec::new();
+    let mut select = Vec::new();
+    for d in drills {
+        let pruned = prune_drilldown(d, fd);
+        group_by.extend(pruned.group_by);
+        select.extend(pruned.select);
+    }
+    (join(group_by, ", "), join(select, ", "))
+}
+
+/// The aggregate a rollup's stored measure was materialized with.
+///
+/// Only the distributive aggregates survive a second roll-up, and each serves exactly one request:
+/// a stored `sum` answers `sum` by re-summing, a stored `count` answers `count` by *summing* the
+/// pre-counted rows (not counting them again), and `min`/`max` answer themselves by re-reducing.
+/// A stored `sum` can't answer `count` (or vice versa) — those are numerically different — and
+/// holistic aggregates like median or a raw average can't be recombined at all, so a query for one
+/// of those falls back to the base fact table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RollupAgg {
+    Sum,
+    Count,
+    Min,
+    Max,
+}
+
+impl RollupAgg {
+    /// Whether a stored measure aggregated this way can satisfy a request for `aggregator`. Each
+    /// stored aggregate serves only its own request — `sum`→`sum`, `count`→`count`, `min`→`min`,
+    /// `max`→`max`; anything else falls back to the base table.
+    fn satisfies(&self, aggregator: &str) -> bool {
+        match self {
+            RollupAgg::Sum => aggregator == "sum",
+            RollupAgg::Count => aggregator == "count",
+            RollupAgg::Min => aggregator == "min",
+            RollupAgg::Max => aggregator == "max",
+        }
+    }
+
+    /// The aggregate to apply *over the rollup* when re-aggregating a satisfied request. A stored
+    /// `count` has already counted the base rows, so it is recombined with `sum` rather than
+    /// `count`; every other aggregate recombines with itself (`sum`/`min`/`max`). This is the
+    /// count→sum rewrite that keeps the substitution from double-counting.
+    fn reaggregator(&self) -> &'static str {
+        match self {
+            RollupAgg::Sum | RollupAgg::Count => "sum",
+            RollupAgg::Min => "min",
+            RollupAgg::Max => "max",
+        }
+    }
+}
+
+/// One measure already summarized by a rollup, named by its base column. A query measure is
+/// answered from this one when it reads the same base column (the rollup stores it under that
+/// name so `sum(col)` over the rollup re-aggregates correctly) and its aggregate is compatible
+/// with `agg` — see [`RollupAgg::satisfies`].
+#[derive(Debug, Clone)]
+pub struct RollupMeasure {
+    pub column: String,
+    pub agg: RollupAgg,
+}
+
+/// A materialized pre-aggregation that summarizes the base fact table at a coarser grain.
+///
+/// `drill_levels` and `cut_dimensions` are the level key columns the rollup is grouped by and
+/// the dimension key columns it still carries for cutting; `measures` are the re-aggregatable
+/// measures it stores. The rollup can answer a query only when it carries every drill key, every
+/// cut dimension, and every measure the query needs — see [`RollupRegistry::resolve`].
+#[derive(Debug, Clone)]
+pub struct Rollup {
+    pub table: TableSql,
+    pub drill_levels: Vec<String>,
+    pub cut_dimensions: Vec<String>,
+    pub measures: Vec<RollupMeasure>,
+}
+
+impl Rollup {
+    /// Whether this rollup covers every requested drilldown, cut dimension and measure.
+    ///
+    /// A drilldown level is covered when the rollup carries that exact key, or when it carries a
+    /// *finer* level of the same hierarchy and can therefore roll up to the requested ancestor
+    /// (`level_columns` runs coarse -> fine, so "finer" means a later entry). A cut dimension is
+    /// covered when the rollup still carries its key column, and a measure when it is stored under
+    /// the same base column with a re-aggregatable aggregate (see [`RollupAgg::satisfies`]).
+    fn covers(&self, drills: &[DrilldownSql], cut_dims: &[String], measures: &[(String, String)]) -> bool {
+        drills.iter().all(|d| self.covers_drilldown(d))
+            && cut_dims.iter().all(|c| self.cut_dimensions.iter().any(|d| d == c))
+            && measures.iter().all(|(col, agg)| self.reaggregator_for(col, agg).is_some())
+    }
+
+    fn has_level(&self, key: &str) -> bool {
+        self.drill_levels.iter().any(|l| l == key)
+    }
+
+    /// Every level of `drill` is either carried directly or reachable by rolling up from a finer
+    /// level the rollup does carry.
+    fn covers_drilldown(&self, drill: &DrilldownSql) -> bool {
+        let levels = &drill.level_columns;
+        levels.iter().enumerate().all(|(i, l)| {
+            self.has_level(&l.key_column)
+                || levels[i + 1..].iter().any(|finer| self.has_level(&finer.key_column))
+        })
+    }
+
+    /// The aggregate to apply over this rollup for a `(column, aggregator)` request, if some stored
+    /// measure on that base column can satisfy it. `None` means the rollup can't answer it.
+    fn reaggregator_for(&self, column: &str, aggregator: &str) -> Option<&'static str> {
+        self.measures.iter()
+            .find(|stored| stored.column == column && stored.agg.satisfies(aggregator))
+            .map(|stored| stored.agg.reaggregator())
+    }
+
+    /// Rewrite a measure so it re-aggregates over this rollup (e.g. a `count` request becomes
+    /// `sum` of the stored counts); unchanged when the rollup doesn't carry it.
+    fn rewrite_measure(&self, mea: &MeasureSql) -> MeasureSql {
+        match self.reaggregator_for(&mea.column, &mea.aggregator) {
+            Some(agg) => MeasureSql { aggregator: agg.to_owned(), column: mea.column.clone() },
+            None => mea.clone(),
+        }
+    }
+
+    /// A [`Table`] handle onto the rollup, used to re-point drilldowns and cuts at it.
+    fn as_table(&self) -> Table {
+        Table { name: self.table.name.clone(), schema: None, primary_key: self.table.primary_key.clone() }
+    }
+
+    /// Rewrite a drilldown to read inline from the rollup: its level/name columns already live on
+    /// the rollup, so pointing `table` at the rollup (with a self-referential key) drops the finer
+    /// dimension join `primary_agg` would otherwise emit against a table the rollup has no key for.
+    fn rewrite_drilldown(&self, drill: &DrilldownSql) -> DrilldownSql {
+        DrilldownSql {
+            foreign_key: drill.foreign_key.clone(),
+            primary_key: drill.foreign_key.clone(),
+            table: self.as_table(),
+            level_columns: drill.level_columns.clone(),
+            property_columns: drill.property_columns.clone(),
+        }
+    }
+
+    /// Rewrite a cut to apply inline against the rollup's carried dimension column, likewise
+    /// dropping the dimension join.
+    fn rewrite_cut(&self, cut: &CutSql) -> CutSql {
+        CutSql {
+            foreign_key: cut.foreign_key.clone(),
+            primary_key: cut.foreign_key.clone(),
+            table: self.as_table(),
+            column: cut.column.clone(),
+            members: cut.members.clone(),
+            member_type: cut.member_type.clone(),
+        }
+    }
+}
+
+/// Registry of rollups consulted before falling back to the base fact table.
+///
+/// Callers register the rollups a cube exposes and then hand the registry to [`calculate`] (or
+/// any other aggregation entry point); the cheapest covering rollup supplies the `a` relation in
+/// place of the base `TableSql`, which drops the finer fact-table joins primary aggregation would
+/// otherwise emit. An empty registry always falls through to the base table.
+#[derive(Debug, Default, Clone)]
+pub struct RollupRegistry {
+    rollups: Vec<Rollup>,
+}
+
+impl RollupRegistry {
+    pub fn new() -> Self {
+        RollupRegistry { rollups: Vec::new() }
+    }
+
+    pub fn register(&mut self, rollup: Rollup) {
+        self.rollups.push(rollup);
+    }
+
+    /// Pick the covering rollup for a query, if any. Among the rollups that cover the requested
+    /// drilldowns, cuts and measures, the one grouped by the fewest levels is coarsest and so the
+    /// cheapest to scan; ties keep registration order. `None` means nothing covers the query and
+    /// the caller should aggregate from the base fact table.
+    pub fn resolve(
+        &self,
+        drills: &[DrilldownSql],
+        cut_dims: &[String],
+        measures: &[(String, String)],
+    ) -> Option<&Rollup>
+    {
+        self.rollups.iter()
+            .filter(|r| r.covers(drills, cut_dims, measures))
+            .min_by_key(|r| r.drill_levels.len())
+    }
+}
+
 pub fn calculate(
     table: &TableSql,
     cuts: &[CutSql],
     drills: &[DrilldownSql],
     meas: &[MeasureSql],
     rca: &RcaSql,
-    ) -> (String, String)
+    cardinality: &RcaCardinality,
+    plan_override: Option<RcaPlan>,
+    fd: &FunctionalDeps,
+    rollups: &RollupRegistry,
+    allow_drill_2_cut: bool,
+    ) -> (String, String, RcaPlan)
 {
+    // Resolve the fact source: if a registered rollup already summarizes every drill level, cut
+    // dimension and measure this query needs, aggregate from it instead of the base fact table.
+    // The rca `a` relation is keyed on (external drills, rca.drill_1, rca.drill_2), so a rollup at
+    // that grain supplies it directly; b, c, d still derive from a exactly as below.
+    let query_drills: Vec<DrilldownSql> = drills.iter()
+        .chain(rca.drill_1.iter())
+        .chain(rca.drill_2.iter())
+        .cloned()
+        .collect();
+    let rollup_cut_dims: Vec<String> = cuts.iter().map(|c| c.column.clone()).collect();
+    let rollup_measures: Vec<(String, String)> = {
+        let mut specs = vec![(rca.mea.column.clone(), rca.mea.aggregator.clone())];
+        specs.extend(meas.iter().map(|m| (m.column.clone(), m.aggregator.clone())));
+        specs
+    };
+
+    // When a rollup covers the query we don't just swap the table name: every drilldown and cut is
+    // rewritten to read inline from the rollup (which already carries those key/name/dimension
+    // columns), so `primary_agg` stops emitting the finer dimension joins it would otherwise try
+    // against tables the rollup has no keys for. Measures are rewritten too, so a `count` request
+    // re-aggregates the rollup's stored counts with `sum` instead of counting them again. With no
+    // covering rollup everything falls through to the base fact table unchanged.
+    let rollup = rollups.resolve(&query_drills, &rollup_cut_dims, &rollup_measures);
+    let (table, drills_owned, cuts_owned, rca_owned, meas_owned) = match rollup {
+        Some(r) => (
+            &r.table,
+            drills.iter().map(|d| r.rewrite_drilldown(d)).collect::<Vec<_>>(),
+            cuts.iter().map(|c| r.rewrite_cut(c)).collect::<Vec<_>>(),
+            RcaSql {
+                drill_1: rca.drill_1.iter().map(|d| r.rewrite_drilldown(d)).collect(),
+                drill_2: rca.drill_2.iter().map(|d| r.rewrite_drilldown(d)).collect(),
+                mea: r.rewrite_measure(&rca.mea),
+            },
+            meas.iter().map(|m| r.rewrite_measure(m)).collect::<Vec<_>>(),
+        ),
+        None => (
+            table,
+            drills.to_vec(),
+            cuts.to_vec(),
+            RcaSql {
+                drill_1: rca.drill_1.clone(),
+                drill_2: rca.drill_2.clone(),
+                mea: rca.mea.clone(),
+            },
+            meas.to_vec(),
+        ),
+    };
+    let drills = &drills_owned[..];
+    let cuts = &cuts_owned[..];
+    let meas = &meas_owned[..];
+    let rca = &rca_owned;
+
     // append the correct rca drill to drilldowns
     // for a, both
     // for b, d2
@@ -72,11 +555,6 @@ pub fn calculate(
 
     c_drills.extend_from_slice(&rca.drill_1);
 
-    println!("a: {:?}", a_drills);
-    println!("b: {:?}", b_drills);
-    println!("c: {:?}", c_drills);
-    println!("d: {:?}", d_drills);
-
     // prepend the rca sql to meas
     let all_meas = {
         let mut temp = vec![rca.mea.clone()];
@@ -116,24 +594,150 @@ pub fn calculate(
         .cloned()
         .collect();
 
-    println!("{:#?}", cuts);
-    println!("{:#?}", ac_cuts);
-    println!("{:#?}", bd_cuts);
+    // Opt-in: a cut on the second rca drill is allowed as a *numerator-only* filter. The cut is
+    // kept out of the pre-aggregation group by (so a and b are still rolled up over every drill_2
+    // member, and c and d — summed before the melt — keep the full comparative-advantage
+    // denominator), then pushed into the pivot's Array Join predicate so only the selected members
+    // survive the melt into a and b. Without the opt-in the drill_2 cut stays blacklisted as before.
+    let drill_2_cut_keys: Vec<_> = rca.drill_2.iter()
+        .flat_map(|d| d.level_columns.iter().map(|l| l.key_column.clone()))
+        .collect();
+    let drill_2_cuts: Vec<&CutSql> = if allow_drill_2_cut {
+        cuts.iter()
+            .filter(|cut| drill_2_cut_keys.iter().any(|k| *k == cut.column))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let drill_2_filter = if drill_2_cuts.is_empty() {
+        String::new()
+    } else {
+        format!(" where {}",
+            join(drill_2_cuts.iter().map(|cut| membership_predicate(cut)), " and "))
+    };
 
     // now aggregate each component
     //
     // As an optimization, c is calculated from a, and d is calculated from b
     // If there's no internal cuts, then b, c, d are calculated from a.
 
+    // Derived-aggregate names are allocated up front so they can't collide with a user column
+    // that happens to be named `a`/`b`/`c`/`d`, and so the measure rename below targets a chosen
+    // identifier rather than a rediscovered one.
+    let mut alias = Alias::new("_rca_");
+    let a_col = alias.tag("a");
+    let b_col = alias.tag("b");
+    let c_col = alias.tag("c");
+    let d_col = alias.tag("d");
+
+    // Wrap a joined (a, b, c, d) relation into the final rca ratio plus any external measures.
+    // Shared by the fast path and the general path so the rca formula lives in one place.
+    let build_final = |a_final_drills: &str, joined: &str| -> String {
+        let final_ext_meas = if !meas.is_empty() {
+            ", ".to_owned() + &join((1..meas.len()+1).map(|i| format!("m{}", i)), ", ")
+        } else {
+            "".to_owned()
+        };
+        let rca_expr = format!("(({a}/{b}) / ({c}/{d})) as rca{ext}",
+            a=a_col, b=b_col, c=c_col, d=d_col, ext=final_ext_meas);
+        format!("select {} from ({})", comma_join(a_final_drills, &rca_expr), joined)
+    };
+
+    // Fast path: if no cut touches an rca drill key, then ac_cuts and bd_cuts are both just
+    // the full cut set, and a, b, c, d all see the same rows. In that case we only need a single
+    // fact-table scan for a (keyed on external drills + drill_1 + drill_2), and b, c, d fall out
+    // as pure reductions over the already-aggregated a relation.
+    //
+    // ClickHouse has no partition operator, so instead of one scan with windowed rollups we emit
+    // b, c, d as separate subqueries over the aggregated a relation and inner-join them back on
+    // their shared keys. That way the fact table is only aggregated once, instead of twice as in
+    // the fallback below. This is a plain-join derivation, so a caller that explicitly forces the
+    // Pivot plan opts out of it.
+    if plan_override != Some(RcaPlan::Pivot)
+        && ac_cuts.len() == cuts.len()
+        && bd_cuts.len() == cuts.len()
+    {
+        let (a, a_final_drills) = primary_agg(table, cuts, &a_drills, &all_meas);
+
+        // the rca measure sits at the front of the measures, so it's always m0; the external
+        // measures follow as m1.. and are carried through from a unchanged.
+        let a = rename_measures(&a, &a_col, meas.len());
+
+        // Materialize a once as a CTE so b, c, d and the join all read the single aggregated
+        // relation by name instead of re-inlining (and so re-running) the fact-table scan. This
+        // is what makes the fast path a true single-scan: the WITH is emitted at the top of the
+        // final statement below.
+        let a_cte = alias.next();
+
+        // reduction keys: external drills are shared by everyone, drill_1 distinguishes c,
+        // drill_2 distinguishes b, and d keeps only the external drills. The join/USING key set
+        // stays the full column list, while the reduction subqueries group and select through the
+        // FD-pruning pass (ancestors carried with any(col), so every key column still surfaces).
+        let ext_drills_str = join(drills.iter().map(|d| d.col_string()), ", ");
+        let drill_1_str = join(rca.drill_1.iter().map(|d| d.col_string()), ", ");
+        let drill_2_str = join(rca.drill_2.iter().map(|d| d.col_string()), ", ");
+
+        let (ext_gb, ext_sel) = grouped_drills(drills, fd);
+        let (d1_gb, d1_sel) = grouped_drills(&rca.drill_1, fd);
+        let (d2_gb, d2_sel) = grouped_drills(&rca.drill_2, fd);
+
+        let c_keys = comma_join(&ext_drills_str, &drill_1_str);
+        let b_keys = comma_join(&ext_drills_str, &drill_2_str);
+
+        // c = each product, all cities (drill_2 summed away)
+        let c = format!("select {sel}, sum({a_col}) as {c_col} from {a_cte} group by {gb}",
+            sel=comma_join(&ext_sel, &d1_sel), gb=comma_join(&ext_gb, &d1_gb),
+            a_col=a_col, c_col=c_col, a_cte=a_cte);
+
+        // b = all products, each city (drill_1 summed away)
+        let b = format!("select {sel}, sum({a_col}) as {b_col} from {a_cte} group by {gb}",
+            sel=comma_join(&ext_sel, &d2_sel), gb=comma_join(&ext_gb, &d2_gb),
+            a_col=a_col, b_col=b_col, a_cte=a_cte);
+
+        // d = all products, all cities (both rca drills summed away)
+        let d = if ext_gb.is_empty() {
+            format!("select sum({a_col}) as {d_col} from {a_cte}", a_col=a_col, d_col=d_col, a_cte=a_cte)
+        } else {
+            format!("select {sel}, sum({a_col}) as {d_col} from {a_cte} group by {gb}",
+                sel=ext_sel, gb=ext_gb, a_col=a_col, d_col=d_col, a_cte=a_cte)
+        };
+
+        // join b and c back onto a on their shared keys, then fold in the scalar/external d. a is
+        // read by CTE name, so the fact table is only scanned and aggregated the once.
+        let mut joined = format!("select * from {a_cte} as {sa} \
+                                  all inner join ({c}) as {sc} using {c_keys} \
+                                  all inner join ({b}) as {sb} using {b_keys}",
+            a_cte=a_cte, sa=alias.next(), c=c, sc=alias.next(), c_keys=c_keys,
+            b=b, sb=alias.next(), b_keys=b_keys);
+
+        joined = if ext_drills_str.is_empty() {
+            format!("select * from ({joined}) as {sj} cross join ({d}) as {sd}",
+                joined=joined, sj=alias.next(), d=d, sd=alias.next())
+        } else {
+            format!("select * from ({joined}) as {sj} all inner join ({d}) as {sd} using {keys}",
+                joined=joined, sj=alias.next(), d=d, sd=alias.next(), keys=ext_drills_str)
+        };
+
+        // bind the single aggregation to the CTE name at the top of the statement.
+        let final_sql = format!("with {a_cte} as ({a}) {body}",
+            a_cte=a_cte, a=a, body=build_final(&a_final_drills, &joined));
+
+        // a true single-scan derivation: one aggregation, referenced by CTE name from b, c, d.
+        return (final_sql, a_final_drills, RcaPlan::Join);
+    }
+
     // First do aggregation for part a, b
     let (a, a_final_drills) = primary_agg(table, &ac_cuts, &a_drills, &all_meas);
     let (b, b_final_drills) = primary_agg(table, &bd_cuts, &b_drills, &all_meas);
 
-    // replace final_m0 with letter name.
-    // I put the rca measure at the beginning of the drills, so it should
-    // always be m0
-    let a = a.replace("final_m0", "a");
-    let b = b.replace("final_m0", "b");
+    // rename the rca measure to its allocated name, and carry the external measures through as
+    // m1.. just like the fast path does. Without the external fold, `a`/`b` keep emitting
+    // final_m1.. while the pivot's groupArray/Array Join clauses below reference m1.., so any RCA
+    // query with both an external measure and an internal cut (which forces this path) would
+    // reference columns that don't exist.
+    // I put the rca measure at the beginning of the measures, so it should always be m0.
+    let a = rename_measures(&a, &a_col, meas.len());
+    let b = rename_measures(&b, &b_col, meas.len());
 
     // for clickhouse, need to make groupArray and Array Join clauses for drill_1 for when
     // aggregating a to c, and b to d.
@@ -159,16 +763,19 @@ pub fn calculate(
         }));
     let join_array_rca_drill_2 = join(join_array_rca_drill_2, ", ");
 
-    // groupArray cols (the drill_2 from rca) can't be included in the group by or select
-    let c_drills_minus_rca_drill_2 = c_drills.iter()
-        .filter(|d| !rca.drill_2.contains(&d))
-        .map(|d| d.col_string());
-    let c_drills_minus_rca_drill_2 = join(c_drills_minus_rca_drill_2, ", ");
+    // groupArray cols (the drill_2 from rca) can't be included in the group by or select; the
+    // rest go through the FD-pruning pass so redundant ancestor keys drop out of the grouping.
+    let c_minus: Vec<_> = c_drills.iter()
+        .filter(|d| !rca.drill_2.contains(d))
+        .cloned()
+        .collect();
+    let (c_minus_gb, c_minus_sel) = grouped_drills(&c_minus, fd);
 
-    let d_drills_minus_rca_drill_2 = d_drills.iter()
-        .filter(|d| !rca.drill_2.contains(&d))
-        .map(|d| d.col_string());
-    let d_drills_minus_rca_drill_2 = join(d_drills_minus_rca_drill_2, ", ");
+    let d_minus: Vec<_> = d_drills.iter()
+        .filter(|d| !rca.drill_2.contains(d))
+        .cloned()
+        .collect();
+    let (d_minus_gb, d_minus_sel) = grouped_drills(&d_minus, fd);
 
     // a and c drills are kept as-is
     let a_drills_str = a_drills.iter()
@@ -180,73 +787,143 @@ pub fn calculate(
     let b_drills_str = join(b_drills_str, ", ");
 
 
-    // Now add part c
-    let ac = format!("select {}, a, c from \
-                      (select {}, {}, groupArray(a) as a_s, sum(a) as c from ({}) group by {}) \
-                      Array Join {}, a_s as a",
-        a_drills_str,
-        c_drills_minus_rca_drill_2,
-        group_array_rca_drill_2,
-        a,
-        c_drills_minus_rca_drill_2,
-        join_array_rca_drill_2,
-    );
-    println!("{}", ac);
-
-    // Now add part d
-    let bd = if d_drills.is_empty() {
-            format!("select {}, b, d from \
-                        (select groupArray(b) as b_s, sum(b) as d from ({})) \
-                        Array Join {}, b_s as b",
-            b_drills_str,
-            b,
-            join_array_rca_drill_2,
-        )
+    // The external measures ride along through the pivot the same way the rca measure does:
+    // collected into an array alongside drill_2 and melted back out, so they survive to the
+    // final select at a's grain (ext + drill_1 + drill_2).
+    let ext_meas_group_array = join(
+        (1..meas.len()+1).map(|i| format!("groupArray(m{i}) as m{i}_s", i=i)), ", ");
+    let ext_meas_array_join = join(
+        (1..meas.len()+1).map(|i| format!("m{i}_s as m{i}", i=i)), ", ");
+    let ext_meas_select = join((1..meas.len()+1).map(|i| format!("m{}", i)), ", ");
+
+    // Choose how to derive c from a and d from b. The pivot keeps a single scan but builds a
+    // groupArray sized by the drill_2 cardinality; the plain join reduces and re-joins, which is
+    // cheaper when drill_2 is large. The caller can override the estimate.
+    // The drill_2 numerator filter only exists in the pivot's melt step, so a present drill_2 cut
+    // forces the Pivot plan — the Join plan has no Array Join to push the predicate into.
+    let plan = if !drill_2_cuts.is_empty() {
+        RcaPlan::Pivot
     } else {
-            format!("select {}, b, d from \
-                        (select {}, {}, groupArray(b) as b_s, sum(b) as d from ({}) group by {}) \
-                        Array Join {}, b_s as b",
-            b_drills_str,
-            d_drills_minus_rca_drill_2,
-            group_array_rca_drill_2,
-            b,
-            d_drills_minus_rca_drill_2,
-            join_array_rca_drill_2,
-        )
+        plan_override.unwrap_or_else(|| cardinality.cheaper_plan())
     };
 
-    println!("bd: {}", bd);
+    // join-plan reduction keys: c rolls drill_2 away (ext + drill_1), d rolls both away (ext).
+    // The USING key set is the full column list; the reductions group/select through FD pruning.
+    let c_keys_join = join(c_drills.iter().map(|d| d.col_string()), ", ");
+    let d_keys_join = join(d_drills.iter().map(|d| d.col_string()), ", ");
+    let (c_gb_join, c_sel_join) = grouped_drills(&c_drills, fd);
+    let (d_gb_join, d_sel_join) = grouped_drills(&d_drills, fd);
+
+    let (ac, bd) = match plan {
+        RcaPlan::Pivot => {
+            // Now add part c.
+            // The inner select only has a group by when there's a non-rca-drill_2 key to group
+            // on; otherwise it rolls the whole relation up, and comma_join keeps the select list
+            // from leading with a dangling comma.
+            let ac_inner = format!("select {} from ({}){}",
+                comma_join(
+                    &c_minus_sel,
+                    &comma_join(
+                        &ext_meas_group_array,
+                        &format!("{ga}, groupArray({a}) as {a}_s, sum({a}) as {c}",
+                            ga=group_array_rca_drill_2, a=a_col, c=c_col),
+                    ),
+                ),
+                a,
+                if c_minus_gb.is_empty() {
+                    String::new()
+                } else {
+                    format!(" group by {}", c_minus_gb)
+                },
+            );
+            let ac = format!("select {} from ({}) Array Join {}{}",
+                comma_join(
+                    &a_drills_str,
+                    &comma_join(&format!("{a}, {c}", a=a_col, c=c_col), &ext_meas_select),
+                ),
+                ac_inner,
+                comma_join(
+                    &format!("{ja}, {a}_s as {a}", ja=join_array_rca_drill_2, a=a_col),
+                    &ext_meas_array_join,
+                ),
+                drill_2_filter,
+            );
+
+            // Now add part d
+            let bd_inner = if d_minus_gb.is_empty() {
+                // even with no grouping key, drill_2 still has to be collected so the outer
+                // Array Join can melt it back out
+                format!("select {ga}, groupArray({b}) as {b}_s, sum({b}) as {d} from ({rel})",
+                    ga=group_array_rca_drill_2, b=b_col, d=d_col, rel=b)
+            } else {
+                format!("select {sel}, {ga}, groupArray({b}) as {b}_s, sum({b}) as {d} from ({rel}) group by {gb}",
+                    sel=d_minus_sel, gb=d_minus_gb, ga=group_array_rca_drill_2, b=b_col, d=d_col, rel=b)
+            };
+            let bd = format!("select {} from ({}) Array Join {}, {b}_s as {b}{filter}",
+                comma_join(&b_drills_str, &format!("{b}, {d}", b=b_col, d=d_col)),
+                bd_inner,
+                join_array_rca_drill_2,
+                b=b_col, d=d_col, filter=drill_2_filter,
+            );
+
+            (ac, bd)
+        }
+        RcaPlan::Join => {
+            // c is a plain reduction of a over (ext + drill_1), inner-joined back onto a. The
+            // external measures and the rca measure a come straight from a at its own grain.
+            let c_sub = format!("select {sel}, sum({a}) as {c} from ({rel}) group by {gb}",
+                sel=c_sel_join, gb=c_gb_join, a=a_col, c=c_col, rel=a);
+            let ac = format!("select {} from ({}) as {} all inner join ({}) as {} using {}",
+                comma_join(
+                    &a_drills_str,
+                    &comma_join(&format!("{a}, {c}", a=a_col, c=c_col), &ext_meas_select),
+                ),
+                a, alias.next(),
+                c_sub, alias.next(),
+                c_keys_join,
+            );
+
+            // d is a plain reduction of b over the external drills; when there are none it is a
+            // scalar total folded in with a cross join.
+            let bd = if d_keys_join.is_empty() {
+                let d_sub = format!("select sum({b}) as {d} from ({rel})",
+                    b=b_col, d=d_col, rel=b);
+                format!("select {} from ({}) as {} cross join ({}) as {}",
+                    comma_join(&b_drills_str, &format!("{b}, {d}", b=b_col, d=d_col)),
+                    b, alias.next(),
+                    d_sub, alias.next(),
+                )
+            } else {
+                let d_sub = format!("select {sel}, sum({b}) as {d} from ({rel}) group by {gb}",
+                    sel=d_sel_join, gb=d_gb_join, b=b_col, d=d_col, rel=b);
+                format!("select {} from ({}) as {} all inner join ({}) as {} using {}",
+                    comma_join(&b_drills_str, &format!("{b}, {d}", b=b_col, d=d_col)),
+                    b, alias.next(),
+                    d_sub, alias.next(),
+                    d_keys_join,
+                )
+            };
+
+            (ac, bd)
+        }
+    };
 
     // now do the final join
 
-    let mut final_sql = format!("select * from ({}) all inner join ({}) using {}",
+    let joined = format!("select * from ({}) as {} all inner join ({}) as {} using {}",
         ac,
+        alias.next(),
         bd,
+        alias.next(),
         b_final_drills,
     );
 
 
     // adding final measures at the end
-    let final_ext_meas = if !meas.is_empty() {
-        ", ".to_owned() + &join((1..meas.len()+1).map(|i| format!("m{}", i)), ", ")
-    } else {
-        "".to_owned()
-    };
-
-    final_sql = format!("select {}, ((a/b) / (c/d)) as rca{} from ({})",
-        a_final_drills,
-        final_ext_meas,
-        final_sql,
-    );
-
-    // SPECIAL CASE
-    // Hack to deal with no drills on d
-    // Later, make this better
-    final_sql = final_sql.replace("select , ", "select ");
-    final_sql = final_sql.replace("group by )", ")");
+    let final_sql = build_final(&a_final_drills, &joined);
 
 
-    (final_sql, a_final_drills)
+    (final_sql, a_final_drills, plan)
 }
 
 #[cfg(test)]
@@ -367,4 +1044,202 @@ mod test {
             "".to_owned()
         );
     }
+
+    // Golden-string tests on the full `calculate` output would be the ideal coverage, but
+    // `calculate` calls `primary_agg`, which lives outside this module's source snapshot, so the
+    // whole-query tests can't be exercised here (the stub above has the same limitation). The
+    // pure helpers that the optimization passes are built from are self-contained, though, so they
+    // are covered directly below.
+
+    fn drill(fk: &str, table: &str, levels: Vec<(&str, Option<&str>)>) -> DrilldownSql {
+        DrilldownSql {
+            foreign_key: fk.into(),
+            primary_key: fk.into(),
+            table: Table { name: table.into(), schema: None, primary_key: None },
+            level_columns: levels.into_iter().map(|(k, n)| LevelColumn {
+                key_column: k.into(),
+                name_column: n.map(|n| n.into()),
+            }).collect(),
+            property_columns: vec![],
+        }
+    }
+
+    #[test]
+    fn test_comma_join() {
+        assert_eq!(comma_join("a, b", "c"), "a, b, c");
+        assert_eq!(comma_join("", "c"), "c");
+        assert_eq!(comma_join("a", ""), "a");
+        assert_eq!(comma_join("", ""), "");
+    }
+
+    #[test]
+    fn test_membership_predicate() {
+        let cut = |members: Vec<&str>, member_type| CutSql {
+            foreign_key: "product_id".into(),
+            primary_key: "product_id".into(),
+            table: Table { name: "dim_products".into(), schema: None, primary_key: None },
+            column: "product_group_id".into(),
+            members: members.into_iter().map(Into::into).collect(),
+            member_type,
+        };
+
+        assert_eq!(
+            membership_predicate(&cut(vec!["3", "7"], MemberType::NonText)),
+            "product_group_id in (3, 7)",
+        );
+        assert_eq!(
+            membership_predicate(&cut(vec!["a", "b"], MemberType::Text)),
+            "product_group_id in ('a', 'b')",
+        );
+    }
+
+    #[test]
+    fn test_rollup_agg_satisfies_and_reaggregator() {
+        // each stored aggregate serves only its own request ...
+        assert!(RollupAgg::Sum.satisfies("sum"));
+        assert!(!RollupAgg::Sum.satisfies("count"));
+        assert!(RollupAgg::Count.satisfies("count"));
+        assert!(!RollupAgg::Count.satisfies("sum"));
+        assert!(RollupAgg::Min.satisfies("min"));
+        assert!(RollupAgg::Max.satisfies("max"));
+        assert!(!RollupAgg::Sum.satisfies("avg"));
+
+        // ... and a stored count re-aggregates via sum, not count.
+        assert_eq!(RollupAgg::Sum.reaggregator(), "sum");
+        assert_eq!(RollupAgg::Count.reaggregator(), "sum");
+        assert_eq!(RollupAgg::Min.reaggregator(), "min");
+        assert_eq!(RollupAgg::Max.reaggregator(), "max");
+    }
+
+    #[test]
+    fn test_cheaper_plan() {
+        // small drill_2: pivot=100+10*5=150 <= join=2*100=200, so the single-scan pivot wins.
+        let small = RcaCardinality { drill_1: 10, drill_2: 5, a_rows: 100 };
+        assert_eq!(small.cheaper_plan(), RcaPlan::Pivot);
+
+        // large drill_2: pivot=1000+100*100_000 dwarfs join=2*1000, so the join avoids the blowup.
+        let large = RcaCardinality { drill_1: 100, drill_2: 100_000, a_rows: 1_000 };
+        assert_eq!(large.cheaper_plan(), RcaPlan::Join);
+    }
+
+    #[test]
+    fn test_prune_drilldown_globally_unique() {
+        // day is globally unique, so it determines month and year: group only by day.
+        let d = drill("date_id", "sales", vec![("year", None), ("month", None), ("day", None)]);
+        let fd = FunctionalDeps::new(vec!["day".into()]);
+        let pruned = prune_drilldown(&d, &fd);
+        assert_eq!(pruned.group_by, vec!["day".to_owned()]);
+        assert_eq!(
+            pruned.select,
+            vec!["any(year) as year".to_owned(), "any(month) as month".to_owned(), "day".to_owned()],
+        );
+    }
+
+    #[test]
+    fn test_prune_drilldown_parent_scoped_keeps_full_key() {
+        // nothing declared globally unique: keep the whole key to stay correct.
+        let d = drill("date_id", "sales", vec![("year", None), ("month", None), ("day", None)]);
+        let fd = FunctionalDeps::new(vec![]);
+        let pruned = prune_drilldown(&d, &fd);
+        assert_eq!(pruned.group_by, vec!["year".to_owned(), "month".to_owned(), "day".to_owned()]);
+        assert_eq!(pruned.select, vec!["year".to_owned(), "month".to_owned(), "day".to_owned()]);
+    }
+
+    #[test]
+    fn test_prune_drilldown_carries_name_columns() {
+        // name columns are always functionally determined by their key, so carried with any().
+        let d = drill("product_id", "dim_products", vec![
+            ("product_group_id", Some("product_group_label")),
+            ("product_id_raw", Some("product_label")),
+        ]);
+        let fd = FunctionalDeps::new(vec!["product_id_raw".into()]);
+        let pruned = prune_drilldown(&d, &fd);
+        assert_eq!(pruned.group_by, vec!["product_id_raw".to_owned()]);
+        assert_eq!(pruned.select, vec![
+            "any(product_group_id) as product_group_id".to_owned(),
+            "any(product_group_label) as product_group_label".to_owned(),
+            "product_id_raw".to_owned(),
+            "any(product_label) as product_label".to_owned(),
+        ]);
+    }
+
+    #[test]
+    fn test_grouped_drills_concatenates() {
+        let a = drill("date_id", "sales", vec![("year", None)]);
+        let b = drill("product_id", "dim_products", vec![("product_id_raw", None)]);
+        let fd = FunctionalDeps::new(vec![]);
+        let (gb, sel) = grouped_drills(&[a, b], &fd);
+        assert_eq!(gb, "year, product_id_raw");
+        assert_eq!(sel, "year, product_id_raw");
+    }
+
+    #[test]
+    fn test_rollup_covers_ancestor_reachability() {
+        let query = drill("date_id", "sales", vec![("year", None), ("month", None), ("day", None)]);
+
+        // rollup keyed at the finest level can roll up to every ancestor.
+        let fine = Rollup {
+            table: TableSql { name: "rollup_day".into(), primary_key: None },
+            drill_levels: vec!["day".into()],
+            cut_dimensions: vec![],
+            measures: vec![RollupMeasure { column: "quantity".into(), agg: RollupAgg::Sum }],
+        };
+        assert!(fine.covers_drilldown(&query));
+
+        // rollup keyed only at year can't recover the finer day.
+        let coarse = Rollup {
+            table: TableSql { name: "rollup_year".into(), primary_key: None },
+            drill_levels: vec!["year".into()],
+            cut_dimensions: vec![],
+            measures: vec![RollupMeasure { column: "quantity".into(), agg: RollupAgg::Sum }],
+        };
+        assert!(!coarse.covers_drilldown(&query));
+    }
+
+    #[test]
+    fn test_rollup_reaggregator_for() {
+        let rollup = Rollup {
+            table: TableSql { name: "rollup".into(), primary_key: None },
+            drill_levels: vec!["day".into()],
+            cut_dimensions: vec![],
+            measures: vec![
+                RollupMeasure { column: "quantity".into(), agg: RollupAgg::Sum },
+                RollupMeasure { column: "events".into(), agg: RollupAgg::Count },
+            ],
+        };
+        assert_eq!(rollup.reaggregator_for("quantity", "sum"), Some("sum"));
+        assert_eq!(rollup.reaggregator_for("quantity", "count"), None);
+        assert_eq!(rollup.reaggregator_for("events", "count"), Some("sum"));
+        assert_eq!(rollup.reaggregator_for("missing", "sum"), None);
+    }
+
+    #[test]
+    fn test_rename_measures_boundary_safe() {
+        // rca measure -> allocated name, external measures -> m{i}.
+        assert_eq!(
+            rename_measures("select final_m0, final_m1 from t", "_rca_a", 1),
+            "select _rca_a, m1 from t",
+        );
+        // final_m1 must not clobber final_m10 (substring hazard of a plain replace fold).
+        assert_eq!(
+            rename_measures("final_m10, final_m1", "_rca_a", 10),
+            "m10, m1",
+        );
+        // a user column that merely ends in final_m0 is left untouched (leading boundary).
+        assert_eq!(
+            rename_measures("myfinal_m0, final_m0", "_rca_a", 0),
+            "myfinal_m0, _rca_a",
+        );
+    }
+
+    #[test]
+    fn test_alias() {
+        let mut alias = Alias::new("_rca_");
+        assert_eq!(alias.tag("a"), "_rca_a");
+        assert_eq!(alias.next(), "_rca_0");
+        assert_eq!(alias.next(), "_rca_1");
+        // tag doesn't consume the subquery counter.
+        assert_eq!(alias.tag("b"), "_rca_b");
+        assert_eq!(alias.next(), "_rca_2");
+    }
 }
\ No newline at end of file